@@ -1,29 +1,19 @@
-use anyhow::Result;
-use libass::{Change, DefaultFontProvider, Layer, Library, Renderer, Track};
+use anyhow::{anyhow, Result};
+use libass::{Change, DefaultFontProvider, Library, Renderer, Track};
 use log::info;
 use log::trace;
-use obs_wrapper::graphics::{GraphicsTexture, MappedTexture};
-use packed_simd_2::{u16x4, u8x4, FromCast};
-use std::{error::Error, fmt::Display, fs, sync::RwLock};
-use subparse::{SsaFile, SubtitleFile};
-
-struct LastLayer {
-    width: usize,
-    height: usize,
-    x: usize,
-    y: usize,
-}
+use std::{error::Error, fmt::Display, fs, path::Path, sync::RwLock};
+use subparse::{SsaFile, SubtitleFile, SubtitleFormat};
 
-impl LastLayer {
-    fn from_layer(layer: &Layer) -> Self {
-        Self {
-            width: layer.width as usize,
-            height: layer.height as usize,
-            x: layer.x as usize,
-            y: layer.y as usize,
-        }
-    }
-}
+use crate::container;
+use crate::gpu::LayerCache;
+
+/// Default/baseline canvas resolution: what the renderer starts with
+/// before `get_properties`'s `canvas_width`/`canvas_height` are applied,
+/// and the resolution below which `set_canvas_size` keeps a 1:1 storage
+/// scale.
+pub const BASELINE_WIDTH: u32 = 1920;
+pub const BASELINE_HEIGHT: u32 = 1080;
 
 struct LoadedTrack {
     pub track: Track<'static>,
@@ -43,7 +33,7 @@ pub struct AssData {
     track: RwLock<Option<LoadedTrack>>,
     cur_time: i64,
 
-    last_image: Vec<LastLayer>,
+    layers: LayerCache,
 }
 
 impl AssData {
@@ -52,7 +42,8 @@ impl AssData {
         let lib_ref = unsafe { lib.as_ref().unwrap() };
         let mut renderer = lib_ref.new_renderer()?;
 
-        renderer.set_frame_size(1920, 1080);
+        renderer.set_frame_size(BASELINE_WIDTH as i32, BASELINE_HEIGHT as i32);
+        renderer.set_storage_size(BASELINE_WIDTH as i32, BASELINE_HEIGHT as i32);
         renderer.set_fonts(
             None,
             "sans-serif",
@@ -68,76 +59,153 @@ impl AssData {
             track: RwLock::new(None),
             cur_time: 0,
 
-            last_image: Vec::with_capacity(4),
+            layers: LayerCache::default(),
         })
     }
 
-    pub fn tick(&mut self, msecs: i64, tex: &mut GraphicsTexture) {
+    pub fn tick(&mut self, msecs: i64) {
         if self.track.read().unwrap().is_none() {
             return;
         }
         self.cur_time = self.cur_time.overflowing_add(msecs).0;
-        self.render(tex);
+        self.render(false);
     }
 
-    fn render(&mut self, dst: &mut GraphicsTexture) {
-        let dst_w = 1920;
+    /// Seeks playback to `msecs`, clamped to `[0, current_len()]`, and
+    /// redraws for the new timestamp.
+    ///
+    /// A jump breaks the assumption `tick` relies on: `render_frame` may
+    /// report `Change::None` even though the previously visible subtitles
+    /// no longer apply to the new timestamp (they belonged to the old,
+    /// monotonic playback position). So a seek always rebuilds the layer
+    /// cache from whatever libass returns, instead of trusting the
+    /// reported change flag.
+    pub fn set_time(&mut self, msecs: i64) {
+        if self.track.read().unwrap().is_none() {
+            return;
+        }
+        self.cur_time = msecs.clamp(0, self.current_len());
+        self.render(true);
+    }
+
+    /// The layers uploaded for the current frame, ready to be drawn with
+    /// `gpu::LayerEffect` in `video_render`.
+    pub fn layers(&self) -> &LayerCache {
+        &self.layers
+    }
 
+    /// Follows the configured canvas resolution instead of the fixed
+    /// `BASELINE_WIDTH`x`BASELINE_HEIGHT` the renderer starts with.
+    ///
+    /// Blur and shadow radii are sized by libass relative to the ratio
+    /// between the frame size and the storage size, so at high canvas
+    /// resolutions (e.g. a 4K or vertical-video canvas) the storage size
+    /// is kept at a baseline-equivalent size rather than tracking the
+    /// frame size 1:1 - otherwise those effects would shrink towards
+    /// invisibility as the canvas grows.
+    ///
+    /// Forces a redraw of the current frame so already-uploaded layers
+    /// aren't left sized/positioned for the previous resolution until the
+    /// next tick or seek happens to come along.
+    pub fn set_canvas_size(&mut self, width: u32, height: u32) {
+        self.renderer.set_frame_size(width as i32, height as i32);
+
+        let dpi_scale = (height as f64 / BASELINE_HEIGHT as f64).max(1.0);
+        let storage_w = (width as f64 / dpi_scale).round() as i32;
+        let storage_h = (height as f64 / dpi_scale).round() as i32;
+        self.renderer.set_storage_size(storage_w, storage_h);
+
+        if self.track.read().unwrap().is_some() {
+            self.render(true);
+        }
+    }
+
+    fn render(&mut self, force_redraw: bool) {
         let (image, change) = {
             let mut track_guard = self.track.write().unwrap();
             let track = track_guard.as_mut().unwrap();
             self.renderer.render_frame(&mut track.track, self.cur_time)
         };
-        if change == Change::None {
+        if change == Change::None && !force_redraw {
             return;
         }
-        info!("New frame, canvas cleared");
-
-        let mut map = dst.map().unwrap();
 
-        clear_last(&self.last_image, &mut map);
-        self.last_image.clear();
-
-        if let Some(image) = image {
-            let mut cnt = 0u64;
-            for layer in image {
-                draw_layer(&layer, &mut map, dst_w);
-                cnt += 1;
-                self.last_image.push(LastLayer::from_layer(&layer));
+        match image {
+            Some(image) => {
+                trace!("Uploading {} layers", image.len());
+                self.layers.rebuild(&image);
             }
-            trace!("Draw {} layers", cnt);
+            // Nothing visible this frame; drop whatever was cached rather
+            // than leaving stale layers keyed to slots libass didn't refill.
+            None => self.layers.clear(),
         }
+        info!("New frame");
     }
 
-    fn load_file(path: &str) -> Result<(Vec<u8>, i64)> {
-        let file_bytes = fs::read(path)?;
-        let file_str = String::from_utf8_lossy(&file_bytes);
+    fn load_file(path: &str, stream_index: Option<i32>) -> Result<(Vec<u8>, i64)> {
+        if container::is_container(path) {
+            let stream_index = stream_index
+                .ok_or_else(|| anyhow!("'{}' is a media container, pick a subtitle stream", path))?;
+            let file_bytes = container::extract_track(path, stream_index)?;
+            let file_str = String::from_utf8_lossy(&file_bytes);
 
-        let parsed: SubtitleFile = SsaFile::parse(&file_str)
-            .map_err(SubtitleParseError::new)?
-            .into();
+            let parsed: SubtitleFile = SsaFile::parse(&file_str)
+                .map_err(SubtitleParseError::new)?
+                .into();
+            let len = track_len(&parsed)?;
 
-        let entries = parsed
-            .get_subtitle_entries()
-            .map_err(SubtitleParseError::new)?;
-        let len = entries
-            .iter()
-            .max_by_key(|ent| ent.timespan.end)
-            .map(|ent| ent.timespan.end.msecs());
+            return Ok((file_bytes, len));
+        }
 
-        Ok((file_bytes, len.unwrap_or(0)))
-    }
+        let file_bytes = fs::read(path)?;
+        let file_str = String::from_utf8_lossy(&file_bytes);
+        let extension = Path::new(path).extension().and_then(|ext| ext.to_str());
+
+        let format =
+            subparse::get_subtitle_format(extension, &file_str).map_err(SubtitleParseError::new)?;
+        let parsed =
+            subparse::parse_str(format, &file_str, 30.0).map_err(SubtitleParseError::new)?;
+        let len = track_len(&parsed)?;
+
+        // libass only understands ASS/SSA; every other format `subparse`
+        // models (SubRip, MicroDVD, WebVTT) is synthesized into a minimal
+        // ASS document so there is a single rendering path below.
+        let ass_bytes = match format {
+            SubtitleFormat::Ssa | SubtitleFormat::Ass => file_bytes,
+            _ => synthesize_ass(&parsed)?.into_bytes(),
+        };
 
-    /// Loads a new track.
-    pub fn load_track(&mut self, path: &str) -> Result<()> {
-        let (file_bytes, track_len) = Self::load_file(path)?;
-        let track = self.lib_ref().new_track_from_memory(&file_bytes, "UTF-8")?;
-        let l_track = LoadedTrack::new(track, track_len);
-        info!("Loaded file {}, length = {} ms.", path, l_track.len);
+        Ok((ass_bytes, len))
+    }
 
-        self.track.write().unwrap().replace(l_track);
-        self.cur_time = 0;
-        Ok(())
+    /// Loads a new track. `path` may be a standalone subtitle file in any
+    /// format `subparse` understands (ASS/SSA, SubRip, MicroDVD, WebVTT) or
+    /// a media container, in which case `stream_index` selects which
+    /// embedded subtitle stream to extract.
+    pub fn load_track(&mut self, path: &str, stream_index: Option<i32>) -> Result<()> {
+        let loaded = Self::load_file(path, stream_index).and_then(|(file_bytes, track_len)| {
+            let track = self.lib_ref().new_track_from_memory(&file_bytes, "UTF-8")?;
+            Ok(LoadedTrack::new(track, track_len))
+        });
+
+        match loaded {
+            Ok(l_track) => {
+                info!("Loaded file {}, length = {} ms.", path, l_track.len);
+                self.track.write().unwrap().replace(l_track);
+                self.cur_time = 0;
+                Ok(())
+            }
+            Err(e) => {
+                // Drop whatever was previously loaded and reset the
+                // clock, so a failed load doesn't leave a stale,
+                // already-ended track around that keeps reporting
+                // `ended() == true` forever.
+                self.track.write().unwrap().take();
+                self.cur_time = 0;
+                self.layers.clear();
+                Err(e)
+            }
+        }
     }
 
     pub fn current_len(&self) -> i64 {
@@ -153,8 +221,13 @@ impl AssData {
         self.cur_time
     }
 
+    /// A track with no parsed entries has `current_len() == 0`, which would
+    /// make this trivially true at `cur_time == 0` right after loading;
+    /// require a positive length so an empty/unparsed track is never
+    /// reported as "ended" and doesn't trigger a busy-loop of auto-advances.
     pub fn ended(&self) -> bool {
-        self.cur_time >= self.current_len()
+        let len = self.current_len();
+        len > 0 && self.cur_time >= len
     }
 
     pub fn loaded(&self) -> bool {
@@ -170,49 +243,134 @@ impl AssData {
     }
 }
 
-fn draw_layer(layer: &Layer, tex: &mut MappedTexture, dst_w: usize) {
-    // RGBA order
-    let mut color = layer.color.to_be_bytes();
-    color[3] = 255 - color[3]; // Inverse alpha
-
-    for y in 0..layer.height as usize {
-        let dst_y = y + layer.y as usize;
-        let dst_y_off = (dst_y * dst_w + layer.x as usize) * 4;
-        let layer_y_off = y * layer.width as usize;
-
-        let src_slice = &layer.bitmap[layer_y_off..layer_y_off + (layer.width as usize)];
-        let dst_slice = &mut tex[dst_y_off..dst_y_off + (layer.width * 4) as usize];
+/// Returns the timestamp of the last subtitle entry in `parsed`, in
+/// milliseconds.
+fn track_len(parsed: &SubtitleFile) -> Result<i64> {
+    let entries = parsed
+        .get_subtitle_entries()
+        .map_err(SubtitleParseError::new)?;
+    Ok(entries
+        .iter()
+        .max_by_key(|ent| ent.timespan.end)
+        .map(|ent| ent.timespan.end.msecs())
+        .unwrap_or(0))
+}
 
-        assert_eq!(dst_slice.len() % 4, 0);
-        assert_eq!(src_slice.len() * 4, dst_slice.len());
+/// Synthesizes a minimal ASS document from a parsed non-ASS subtitle file
+/// (SubRip, MicroDVD, WebVTT, ...), so libass has a single format to render.
+fn synthesize_ass(parsed: &SubtitleFile) -> Result<String> {
+    let entries = parsed
+        .get_subtitle_entries()
+        .map_err(SubtitleParseError::new)?;
+
+    let mut doc = ass_header("Default");
+    for entry in &entries {
+        let text = match &entry.line {
+            Some(line) => translate_html_tags(&line.to_string()),
+            None => continue,
+        };
+        if text.is_empty() {
+            continue;
+        }
+        doc.push_str(&dialogue_line(
+            entry.timespan.start.msecs(),
+            entry.timespan.end.msecs(),
+            "Default",
+            &text,
+        ));
+    }
 
-        dst_slice
-            .chunks_exact_mut(4)
-            .zip(src_slice)
-            .for_each(|(dst_chunk, k)| {
-                let k = *k;
+    Ok(doc)
+}
 
-                let mut arr = u16x4::from_cast(u8x4::from_slice_unaligned(dst_chunk));
-                arr *= (255 - k) as u16;
+/// Translates the handful of inline HTML tags subtitle formats commonly use
+/// (`<b>`, `<i>`) into ASS override codes, drops any other tag (e.g.
+/// `<font>`), and escapes literal `{`/`}` so source text can't be mistaken
+/// for an ASS override block by libass.
+pub(crate) fn translate_html_tags(text: &str) -> String {
+    let text = text
+        .replace("<b>", "\u{1}b1")
+        .replace("</b>", "\u{1}b0")
+        .replace("<i>", "\u{1}i1")
+        .replace("</i>", "\u{1}i0");
+
+    let mut out = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '\u{1}' => {
+                // Placeholder for a `<b>`/`<i>` tag translated above; emit
+                // the real ASS override now that braces are out of the way.
+                let tag: String = chars.by_ref().take(2).collect();
+                out.push_str("{\\");
+                out.push_str(&tag);
+                out.push('}');
+            }
+            '{' => out.push_str("\\{"),
+            '}' => out.push_str("\\}"),
+            '\n' => out.push_str("\\N"),
+            '<' => {
+                // Strip any other tag (e.g. `<font color=...>`, `</font>`)
+                // instead of letting it leak into the rendered line. If
+                // there's no closing `>` (a stray/unterminated `<`), it
+                // wasn't a tag after all; re-emit `<` and everything after
+                // it verbatim instead of consuming the rest of the line.
+                let mut consumed = String::new();
+                let mut closed = false;
+                while let Some(next) = chars.next() {
+                    if next == '>' {
+                        closed = true;
+                        break;
+                    }
+                    consumed.push(next);
+                }
+                if !closed {
+                    out.push('<');
+                    out.push_str(&consumed);
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+    out
+}
 
-                let mut color_premul = u16x4::from_cast(u8x4::from_slice_unaligned(&color));
-                color_premul *= k as u16;
+/// Builds a minimal `[Script Info]`/`[V4+ Styles]` ASS header with a single
+/// default style, ready to have `Dialogue:` lines appended to it.
+pub(crate) fn ass_header(style_name: &str) -> String {
+    format!(
+        "[Script Info]\n\
+         ScriptType: v4.00+\n\
+         \n\
+         [V4+ Styles]\n\
+         Format: Name, Fontname, Fontsize, PrimaryColour, SecondaryColour, OutlineColour, BackColour, Bold, Italic, Underline, StrikeOut, ScaleX, ScaleY, Spacing, Angle, BorderStyle, Outline, Shadow, Alignment, MarginL, MarginR, MarginV, Encoding\n\
+         Style: {},Sans Serif,48,&H00FFFFFF,&H000000FF,&H00000000,&H80000000,0,0,0,0,100,100,0,0,1,2,1,2,10,10,10,1\n\
+         \n\
+         [Events]\n\
+         Format: Layer, Start, End, Style, Name, MarginL, MarginR, MarginV, Effect, Text\n",
+        style_name
+    )
+}
 
-                let result = u8x4::from_cast((arr + color_premul) / 255);
-                result.write_to_slice_unaligned(dst_chunk);
-            });
-    }
+/// Formats a single `Dialogue:` line for the given millisecond range.
+pub(crate) fn dialogue_line(start_ms: i64, end_ms: i64, style: &str, text: &str) -> String {
+    format!(
+        "Dialogue: 0,{},{},{},,0,0,0,,{}\n",
+        format_ass_time(start_ms),
+        format_ass_time(end_ms),
+        style,
+        text
+    )
 }
 
-fn clear_last(image: &[LastLayer], tex: &mut MappedTexture) {
-    let tex_w = tex.width() as usize;
-    for layer in image {
-        for dst_y in layer.y..layer.y + layer.height {
-            let dst_y_off = (dst_y * tex_w + layer.x as usize) * 4;
-            let dst_slice = &mut tex[dst_y_off..dst_y_off + (layer.width * 4) as usize];
-            dst_slice.fill(0);
-        }
-    }
+/// Formats milliseconds as an ASS timestamp, `H:MM:SS.cc`.
+fn format_ass_time(msecs: i64) -> String {
+    let msecs = msecs.max(0);
+    let centis = (msecs / 10) % 100;
+    let secs = (msecs / 1000) % 60;
+    let mins = (msecs / 1000 / 60) % 60;
+    let hours = msecs / 1000 / 60 / 60;
+    format!("{}:{:02}:{:02}.{:02}", hours, mins, secs, centis)
 }
 
 #[derive(Debug)]
@@ -230,3 +388,46 @@ impl Display for SubtitleParseError {
     }
 }
 impl Error for SubtitleParseError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn format_ass_time_pads_and_wraps_fields() {
+        assert_eq!(format_ass_time(0), "0:00:00.00");
+        assert_eq!(format_ass_time(1_234), "0:00:01.23");
+        assert_eq!(format_ass_time(61_000), "0:01:01.00");
+        assert_eq!(format_ass_time(3_661_000), "1:01:01.00");
+    }
+
+    #[test]
+    fn format_ass_time_clamps_negative_values() {
+        assert_eq!(format_ass_time(-500), "0:00:00.00");
+    }
+
+    #[test]
+    fn translate_html_tags_converts_bold_and_italic() {
+        assert_eq!(translate_html_tags("<b>hi</b>"), "{\\b1}hi{\\b0}");
+        assert_eq!(translate_html_tags("<i>hi</i>"), "{\\i1}hi{\\i0}");
+    }
+
+    #[test]
+    fn translate_html_tags_strips_unsupported_tags() {
+        assert_eq!(
+            translate_html_tags("<font color=\"red\">hi</font>"),
+            "hi"
+        );
+    }
+
+    #[test]
+    fn translate_html_tags_escapes_literal_braces_and_newlines() {
+        assert_eq!(translate_html_tags("{not a tag}"), "\\{not a tag\\}");
+        assert_eq!(translate_html_tags("line one\nline two"), "line one\\Nline two");
+    }
+
+    #[test]
+    fn translate_html_tags_keeps_unterminated_tag_literal() {
+        assert_eq!(translate_html_tags("hello <b"), "hello <b");
+    }
+}
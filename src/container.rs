@@ -0,0 +1,163 @@
+//! Probing and extraction of subtitle streams muxed inside a media
+//! container (`.mkv`, `.mp4`, ...), as opposed to a standalone subtitle
+//! file on disk.
+
+use anyhow::{anyhow, Result};
+use ffmpeg_next as ffmpeg;
+use ffmpeg_next::codec::subtitle::Rect;
+use log::warn;
+use std::path::Path;
+
+/// Extensions treated as containers that may carry an embedded subtitle
+/// track, rather than a standalone subtitle file handled by `subparse`.
+const CONTAINER_EXTENSIONS: &[&str] = &["mkv", "mp4", "mov", "webm", "avi", "ts", "m4v"];
+
+pub fn is_container(path: &str) -> bool {
+    Path::new(path)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| {
+            CONTAINER_EXTENSIONS
+                .iter()
+                .any(|candidate| candidate.eq_ignore_ascii_case(ext))
+        })
+        .unwrap_or(false)
+}
+
+/// Metadata for one subtitle stream inside a media container, as reported
+/// by ffmpeg's format probe. Used to populate the stream-picker dropdown
+/// in `get_properties`.
+#[derive(Debug, Clone)]
+pub struct SubtitleStreamInfo {
+    pub index: i32,
+    pub codec: String,
+    pub language: Option<String>,
+    pub title: Option<String>,
+}
+
+impl SubtitleStreamInfo {
+    /// A human-readable label for the `get_properties` dropdown, e.g.
+    /// `#2 [eng] Signs & Songs (ass)`.
+    pub fn label(&self) -> String {
+        let lang = self.language.as_deref().unwrap_or("und");
+        match &self.title {
+            Some(title) => format!("#{} [{}] {} ({})", self.index, lang, title, self.codec),
+            None => format!("#{} [{}] ({})", self.index, lang, self.codec),
+        }
+    }
+}
+
+/// Probes `path` with ffmpeg and returns every subtitle stream it contains.
+pub fn probe_subtitle_streams(path: &str) -> Result<Vec<SubtitleStreamInfo>> {
+    ffmpeg::init()?;
+    let input = ffmpeg::format::input(&path)?;
+
+    let streams = input
+        .streams()
+        .filter(|stream| stream.parameters().medium() == ffmpeg::media::Type::Subtitle)
+        .map(|stream| {
+            let codec = ffmpeg::codec::context::Context::from_parameters(stream.parameters())
+                .ok()
+                .and_then(|ctx| ctx.codec())
+                .map(|codec| codec.name().to_string())
+                .unwrap_or_else(|| "unknown".to_string());
+
+            let meta = stream.metadata();
+            SubtitleStreamInfo {
+                index: stream.index() as i32,
+                codec,
+                language: meta.get("language").map(str::to_string),
+                title: meta.get("title").map(str::to_string),
+            }
+        })
+        .collect();
+
+    Ok(streams)
+}
+
+/// Extracts the subtitle stream at `stream_index` out of the container at
+/// `path`, returning a full ASS document libass can load via
+/// `new_track_from_memory`.
+///
+/// Every codec (ASS/SSA included) is routed through ffmpeg's subtitle
+/// decoder rather than being demuxed as raw packet bytes: an ASS packet's
+/// payload is a single reordered event row
+/// (`ReadOrder,Layer,Style,Name,...,Text`), not a standalone `Dialogue:`
+/// line, and the `[Script Info]`/`[V4+ Styles]`/`[Events]` header lives in
+/// the stream's `extradata`, not in any packet. The decoder reassembles
+/// both into proper `Dialogue:` lines (`Rect::Ass`) we can just append.
+pub fn extract_track(path: &str, stream_index: i32) -> Result<Vec<u8>> {
+    ffmpeg::init()?;
+    let mut input = ffmpeg::format::input(&path)?;
+    decode_to_ass(&mut input, stream_index)
+}
+
+/// Decodes every packet on `stream_index` with ffmpeg's subtitle decoder
+/// and assembles an ASS document from the resulting rects: `Rect::Ass`
+/// rows are already-formatted `Dialogue:` lines, `Rect::Text` rows (plain
+/// SubRip/WebVTT-style text) are wrapped into one using the packet's
+/// timing. Bitmap-only rects have no text representation and are dropped.
+fn decode_to_ass(input: &mut ffmpeg::format::context::Input, stream_index: i32) -> Result<Vec<u8>> {
+    let stream = input
+        .stream(stream_index as usize)
+        .ok_or_else(|| anyhow!("no stream with index {}", stream_index))?;
+    let time_base: f64 = stream.time_base().into();
+    let parameters = stream.parameters();
+
+    // ASS/SSA streams carry their `[Script Info]`/`[V4+ Styles]`/`[Events]`
+    // header in `extradata`; anything else gets our own minimal header.
+    let mut doc = parameters
+        .extradata()
+        .map(|data| String::from_utf8_lossy(data).into_owned())
+        .filter(|header| header.contains("[Events]"))
+        .unwrap_or_else(|| crate::ass::ass_header("Default"));
+    if !doc.ends_with('\n') {
+        doc.push('\n');
+    }
+
+    let mut decoder = ffmpeg::codec::context::Context::from_parameters(parameters)?
+        .decoder()
+        .subtitle()?;
+    let mut subtitle = Default::default();
+
+    for (packet_stream, packet) in input.packets() {
+        if packet_stream.index() as i32 != stream_index {
+            continue;
+        }
+        if decoder.decode(&packet, &mut subtitle)? == 0 {
+            continue;
+        }
+
+        let start_ms = (packet.pts().unwrap_or(0) as f64 * time_base * 1000.0) as i64;
+        let dur_ms = (packet.duration() as f64 * time_base * 1000.0) as i64;
+        let end_ms = start_ms + dur_ms.max(0);
+
+        for rect in subtitle.rects() {
+            match rect {
+                Rect::Ass(ass) => {
+                    let line = ass.get();
+                    if !line.is_empty() {
+                        doc.push_str(line);
+                        doc.push('\n');
+                    }
+                }
+                Rect::Text(text) => {
+                    let text = crate::ass::translate_html_tags(text.get());
+                    if !text.is_empty() {
+                        doc.push_str(&crate::ass::dialogue_line(start_ms, end_ms, "Default", &text));
+                    }
+                }
+                Rect::Bitmap(_) => {
+                    warn!(
+                        "Dropping bitmap subtitle rect on stream {} at {}ms: \
+                         bitmap-only subtitles (e.g. PGS/VobSub/DVD) have no \
+                         text representation and render as nothing",
+                        stream_index, start_ms
+                    );
+                }
+            }
+        }
+    }
+
+    Ok(doc.into_bytes())
+}
@@ -0,0 +1,136 @@
+//! GPU-side compositing of libass layers.
+//!
+//! Each libass `Layer` bitmap is uploaded as its own small single-channel
+//! (alpha) texture and drawn with an OBS effect that multiplies the
+//! layer's color by the sampled alpha, instead of mapping the whole
+//! output canvas and blending every layer pixel-by-pixel on the CPU.
+
+use libass::Layer;
+use obs_wrapper::graphics::{GraphicsColorFormat, GraphicsEffect, GraphicsTexture};
+
+const LAYER_EFFECT_SOURCE: &str = include_str!("layer.effect");
+
+/// One libass layer uploaded to the GPU: an alpha-only texture plus where
+/// and in what color it should be drawn.
+pub struct GpuLayer {
+    pub texture: GraphicsTexture,
+    pub x: i32,
+    pub y: i32,
+    pub color: [f32; 4],
+
+    width: u32,
+    height: u32,
+    bitmap: Vec<u8>,
+}
+
+impl GpuLayer {
+    fn upload(layer: &Layer) -> Self {
+        let mut texture = GraphicsTexture::new(
+            layer.width as u32,
+            layer.height as u32,
+            GraphicsColorFormat::R8,
+        );
+        {
+            let mut map = texture.map().unwrap();
+            map.copy_from_slice(&layer.bitmap);
+        }
+
+        Self {
+            texture,
+            x: layer.x as i32,
+            y: layer.y as i32,
+            color: Self::color_of(layer),
+
+            width: layer.width as u32,
+            height: layer.height as u32,
+            bitmap: layer.bitmap.clone(),
+        }
+    }
+
+    /// Refreshes position/color (cheap, done unconditionally) and only
+    /// re-uploads the bitmap to the GPU texture if libass actually
+    /// repainted these pixels, rather than blindly re-uploading every
+    /// layer on every change event.
+    fn update(&mut self, layer: &Layer) {
+        self.x = layer.x as i32;
+        self.y = layer.y as i32;
+        self.color = Self::color_of(layer);
+
+        if self.bitmap != layer.bitmap {
+            let mut map = self.texture.map().unwrap();
+            map.copy_from_slice(&layer.bitmap);
+            self.bitmap.clear();
+            self.bitmap.extend_from_slice(&layer.bitmap);
+        }
+    }
+
+    fn same_size(&self, layer: &Layer) -> bool {
+        self.width == layer.width as u32 && self.height == layer.height as u32
+    }
+
+    /// RGBA order, with libass's alpha inverted like the old CPU path.
+    fn color_of(layer: &Layer) -> [f32; 4] {
+        let rgba = layer.color.to_be_bytes();
+        [
+            rgba[0] as f32 / 255.0,
+            rgba[1] as f32 / 255.0,
+            rgba[2] as f32 / 255.0,
+            (255 - rgba[3]) as f32 / 255.0,
+        ]
+    }
+}
+
+/// Holds the layer textures uploaded for the current frame, keyed by a
+/// layer's position in libass's (stable, z-ordered) output list rather than
+/// a full wholesale replacement every redraw: most layers are unmoved
+/// dialogue/karaoke text that didn't change between one change event and
+/// the next, and many-layer subtitles (e.g. per-character karaoke) made a
+/// full re-upload pass the dominant cost of `rebuild`.
+#[derive(Default)]
+pub struct LayerCache {
+    layers: Vec<GpuLayer>,
+}
+
+impl LayerCache {
+    pub fn rebuild(&mut self, layers: &[Layer]) {
+        for (i, layer) in layers.iter().enumerate() {
+            match self.layers.get_mut(i) {
+                Some(cached) if cached.same_size(layer) => cached.update(layer),
+                Some(cached) => *cached = GpuLayer::upload(layer),
+                None => self.layers.push(GpuLayer::upload(layer)),
+            }
+        }
+        self.layers.truncate(layers.len());
+    }
+
+    pub fn clear(&mut self) {
+        self.layers.clear();
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &GpuLayer> {
+        self.layers.iter()
+    }
+}
+
+/// The OBS effect that composites one `GpuLayer` onto the current render
+/// target, letting the GPU do the premultiplied-alpha blend the SIMD code
+/// used to do by hand.
+pub struct LayerEffect {
+    effect: GraphicsEffect,
+}
+
+impl LayerEffect {
+    pub fn new() -> Self {
+        Self {
+            effect: GraphicsEffect::from_source(LAYER_EFFECT_SOURCE)
+                .expect("layer.effect failed to compile"),
+        }
+    }
+
+    pub fn draw(&mut self, layer: &GpuLayer) {
+        self.effect.set_texture_param("image", &layer.texture);
+        self.effect.set_vec4_param("color", layer.color);
+        self.effect
+            .draw_sprite(&layer.texture, layer.x, layer.y);
+    }
+}
@@ -1,13 +1,16 @@
-#![feature(slice_fill)]
 use std::{borrow::Cow, cell::RefCell, sync::RwLock};
 
-use obs_wrapper::graphics::GraphicsColorFormat;
-use obs_wrapper::graphics::GraphicsTexture;
 use obs_wrapper::{log::Logger, obs_register_module, obs_string, prelude::*, source::*};
 mod ass;
+mod container;
+mod gpu;
+mod playlist;
 
 use ass::AssData;
+use container::SubtitleStreamInfo;
+use gpu::LayerEffect;
 use log::*;
+use playlist::{Playlist, RepeatMode};
 
 macro_rules! ensure_data {
     ($i:ident) => {
@@ -26,20 +29,102 @@ struct SubtitleModule {
 struct SourceData {
     _src: SourceContext,
     ass: AssData,
-    tex: GraphicsTexture,
+    effect: LayerEffect,
     state: MediaState,
 
     canvas_w: u32,
     canvas_h: u32,
 
-    playlist: RwLock<Vec<String>>,
+    playlist: RwLock<Playlist>,
+
+    /// Subtitle streams found the last time the current playlist entry was
+    /// probed, if it points at a media container. Backs the stream-picker
+    /// dropdown in `get_properties`.
+    probed_streams: Vec<SubtitleStreamInfo>,
+
+    /// The path `probed_streams` was last probed for, so `update()` (which
+    /// also runs on e.g. a canvas-size slider drag) only re-runs ffmpeg's
+    /// synchronous container probe when the current playlist entry itself
+    /// changed.
+    last_probed_path: Option<String>,
 }
 
 impl SourceData {
-    fn load_track(&mut self, path: &str) {
-        // "/home/harryc/workspace/obs-subtitle/subs/test1.ass"
-        if let Err(e) = self.ass.load_track(path) {
-            error!("Failed to load track: {}", e);
+    fn load_track(&mut self, path: &str, stream_index: Option<i32>) {
+        match self.ass.load_track(path, stream_index) {
+            Ok(()) => self.playlist.write().unwrap().mark_playing(),
+            Err(e) => {
+                error!("Failed to load track '{}': {}", path, e);
+                self.skip_failed_track();
+            }
+        }
+    }
+
+    /// The current track failed to load; advance past it so one unreadable
+    /// file doesn't stall auto-advance for the rest of the playlist. Walks
+    /// the playlist at most once (every other track, then the failed one
+    /// again last), in case none of them load.
+    fn skip_failed_track(&mut self) {
+        let attempts = self.playlist.read().unwrap().len();
+        for _ in 0..attempts {
+            let path = match self.playlist.write().unwrap().advance_linear() {
+                Some(path) => path,
+                None => return,
+            };
+            self.refresh_probe(&path);
+            let stream_index = self.probed_streams.first().map(|s| s.index);
+            match self.ass.load_track(&path, stream_index) {
+                Ok(()) => {
+                    self.playlist.write().unwrap().mark_playing();
+                    return;
+                }
+                Err(e) => error!("Failed to load track '{}': {}", path, e),
+            }
+        }
+    }
+
+    /// Advances the playlist (per its repeat mode) and loads whatever
+    /// track it lands on, if any.
+    fn advance_playlist(&mut self, next: impl FnOnce(&mut Playlist) -> Option<String>) {
+        let path = next(&mut self.playlist.write().unwrap());
+        if let Some(path) = path {
+            self.load_probed_track(&path);
+        }
+    }
+
+    /// Probes `path` and loads it, defaulting to its first subtitle stream
+    /// if it turns out to be a media container. Playlist navigation (auto-
+    /// advance, next/previous, failure recovery) has no settings dialog to
+    /// read a user-picked `subtitle_stream` from, so this is the closest
+    /// it can get to the fallback `update()` applies on the initial load.
+    fn load_probed_track(&mut self, path: &str) {
+        self.refresh_probe(path);
+        let stream_index = self.probed_streams.first().map(|s| s.index);
+        self.load_track(path, stream_index);
+    }
+
+    /// Re-probes `path` for embedded subtitle streams if it is a media
+    /// container, refreshing `probed_streams` for the properties dropdown.
+    fn refresh_probe(&mut self, path: &str) {
+        self.probed_streams = if container::is_container(path) {
+            container::probe_subtitle_streams(path).unwrap_or_else(|e| {
+                error!("Failed to probe '{}' for subtitle streams: {}", path, e);
+                Vec::new()
+            })
+        } else {
+            Vec::new()
+        };
+        self.last_probed_path = Some(path.to_string());
+    }
+
+    /// Same as `refresh_probe`, but skips the (synchronous, potentially
+    /// slow) probe if `path` is the one already reflected in
+    /// `probed_streams` — so settings changes unrelated to the playlist
+    /// (e.g. dragging the canvas-size sliders) don't re-probe on every
+    /// `update()` call.
+    fn refresh_probe_if_changed(&mut self, path: &str) {
+        if self.last_probed_path.as_deref() != Some(path) {
+            self.refresh_probe(path);
         }
     }
 }
@@ -65,11 +150,11 @@ impl CreatableSource<SourceData> for SubtitleModule {
         context: &mut CreatableSourceContext<SourceData>,
         mut source: SourceContext,
     ) -> SourceData {
-        let width = 1920;
-        let height = 1080;
+        let width = ass::BASELINE_WIDTH;
+        let height = ass::BASELINE_HEIGHT;
 
-        let tex = GraphicsTexture::new(width as u32, height as u32, GraphicsColorFormat::RGBA);
         let ass = AssData::new().unwrap();
+        let effect = LayerEffect::new();
 
         context.register_hotkey(
             obs_string!("Preheat.PlayPause"),
@@ -82,11 +167,33 @@ impl CreatableSource<SourceData> for SubtitleModule {
             },
         );
 
+        context.register_hotkey(
+            obs_string!("Preheat.Next"),
+            obs_string!("Next Track"),
+            |key, data| {
+                if key.pressed {
+                    let data = ensure_data!(data);
+                    data.advance_playlist(|playlist| playlist.next());
+                }
+            },
+        );
+
+        context.register_hotkey(
+            obs_string!("Preheat.Previous"),
+            obs_string!("Previous Track"),
+            |key, data| {
+                if key.pressed {
+                    let data = ensure_data!(data);
+                    data.advance_playlist(|playlist| playlist.previous());
+                }
+            },
+        );
+
         source.update_source_settings(&context.settings);
 
         let data = SourceData {
             _src: source,
-            tex,
+            effect,
 
             ass,
             state: MediaState::Playing,
@@ -94,7 +201,9 @@ impl CreatableSource<SourceData> for SubtitleModule {
             canvas_h: height,
             canvas_w: width,
 
-            playlist: Default::default(),
+            playlist: RwLock::new(Playlist::new()),
+            probed_streams: Vec::new(),
+            last_probed_path: None,
         };
 
         data
@@ -108,12 +217,14 @@ impl VideoRenderSource<SourceData> for SubtitleModule {
         _render: &mut VideoRenderContext,
     ) {
         let data = ensure_data!(data);
-        data.tex.draw(0, 0, 0, 0, false);
+        for layer in data.ass.layers().iter() {
+            data.effect.draw(layer);
+        }
     }
 }
 
 impl GetPropertiesSource<SourceData> for SubtitleModule {
-    fn get_properties(_data: &mut Option<SourceData>, properties: &mut Properties) {
+    fn get_properties(data: &mut Option<SourceData>, properties: &mut Properties) {
         properties
             .add_int(
                 obs_string!("canvas_height"),
@@ -135,9 +246,37 @@ impl GetPropertiesSource<SourceData> for SubtitleModule {
                 obs_string!("playlist"),
                 obs_string!("Playlist"),
                 EditableListType::Files,
-                obs_string!("ASS subtitle file (*.ass)"),
+                obs_string!("Media file or subtitle file (*.ass *.ssa *.srt *.vtt *.sub *.mkv *.mp4 *.mov *.webm *.avi *.ts *.m4v)"),
                 obs_string!(""),
             );
+
+        let mut repeat_mode = properties.add_list(
+            obs_string!("repeat_mode"),
+            obs_string!("Repeat"),
+            ListType::Int,
+            ListFormat::Int,
+        );
+        repeat_mode.add_item("Off", 0);
+        repeat_mode.add_item("Repeat One", 1);
+        repeat_mode.add_item("Repeat All", 2);
+        repeat_mode.add_item("Shuffle", 3);
+
+        // Populated from the last probe of the current playlist entry, so
+        // the dropdown only shows entries when that entry is a media
+        // container with embedded subtitle streams to choose between.
+        let streams = data
+            .as_ref()
+            .map(|data| data.probed_streams.as_slice())
+            .unwrap_or(&[]);
+        let mut list = properties.add_list(
+            obs_string!("subtitle_stream"),
+            obs_string!("Subtitle Stream"),
+            ListType::Int,
+            ListFormat::Int,
+        );
+        for stream in streams {
+            list.add_item(&stream.label(), stream.index);
+        }
     }
 }
 
@@ -145,24 +284,59 @@ impl UpdateSource<SourceData> for SubtitleModule {
     fn update(data: &mut Option<SourceData>, settings: &mut DataObj, _context: &mut GlobalContext) {
         let data = ensure_data!(data);
 
-        let mut playlist = data.playlist.write().unwrap();
-        playlist.clear();
+        let canvas_w = settings
+            .get::<f64>(obs_string!("canvas_width"))
+            .map(|v| v as u32)
+            .unwrap_or(data.canvas_w);
+        let canvas_h = settings
+            .get::<f64>(obs_string!("canvas_height"))
+            .map(|v| v as u32)
+            .unwrap_or(data.canvas_h);
+        if canvas_w != data.canvas_w || canvas_h != data.canvas_h {
+            data.canvas_w = canvas_w;
+            data.canvas_h = canvas_h;
+            data.ass.set_canvas_size(canvas_w, canvas_h);
+        }
 
         let new_list: DataArray = settings.get(obs_string!("playlist")).unwrap();
-
+        let mut tracks = Vec::with_capacity(new_list.len());
         for i in 0..new_list.len() {
             let item = new_list.get(i).unwrap();
             let path: Cow<str> = item.get(obs_string!("value")).unwrap();
             info!("New playlist path: {}", path);
-            playlist.push(path.into_owned());
+            tracks.push(path.into_owned());
         }
 
-        println!("{} {}", data.ass.loaded(), playlist.is_empty());
+        let repeat_mode = settings
+            .get::<f64>(obs_string!("repeat_mode"))
+            .map(|v| RepeatMode::from_index(v as i32))
+            .unwrap_or(RepeatMode::Off);
 
-        if !data.ass.loaded() && !playlist.is_empty() {
-            let path = playlist[0].clone();
-            drop(playlist);
-            data.load_track(&path);
+        let current_path = {
+            let mut playlist = data.playlist.write().unwrap();
+            playlist.set_tracks(tracks);
+            playlist.repeat = repeat_mode;
+            playlist.current_path().map(str::to_string)
+        };
+
+        if let Some(path) = &current_path {
+            data.refresh_probe_if_changed(path);
+        }
+
+        if !data.ass.loaded() {
+            if let Some(path) = current_path {
+                // The dropdown setting is unset (and otherwise reads as
+                // `0`, normally the video stream) until the user opens it
+                // and picks something, so fall back to the first subtitle
+                // stream the last probe found rather than failing to load
+                // anything.
+                let stream_index: Option<i32> = settings
+                    .get::<f64>(obs_string!("subtitle_stream"))
+                    .ok()
+                    .map(|v| v as i32)
+                    .or_else(|| data.probed_streams.first().map(|s| s.index));
+                data.load_track(&path, stream_index);
+            }
         }
     }
 }
@@ -214,15 +388,40 @@ impl MediaGetDurationSource<SourceData> for SubtitleModule {
     }
 }
 
+impl MediaSetTimeSource<SourceData> for SubtitleModule {
+    fn set_time(data: &mut Option<SourceData>, milliseconds: i64) {
+        let data = ensure_data!(data);
+        data.ass.set_time(milliseconds);
+    }
+}
+
 impl VideoTickSource<SourceData> for SubtitleModule {
     fn video_tick(data: &mut Option<SourceData>, seconds: f32) {
         let data = ensure_data!(data);
         if data.state == MediaState::Playing {
-            data.ass.tick((seconds * 1000.0) as i64, &mut data.tex);
+            data.ass.tick((seconds * 1000.0) as i64);
+
+            if data.ass.ended() {
+                data.advance_playlist(|playlist| playlist.on_track_ended());
+            }
         }
     }
 }
 
+impl MediaNextSource<SourceData> for SubtitleModule {
+    fn next(data: &mut Option<SourceData>) {
+        let data = ensure_data!(data);
+        data.advance_playlist(|playlist| playlist.next());
+    }
+}
+
+impl MediaPreviousSource<SourceData> for SubtitleModule {
+    fn previous(data: &mut Option<SourceData>) {
+        let data = ensure_data!(data);
+        data.advance_playlist(|playlist| playlist.previous());
+    }
+}
+
 impl Module for SubtitleModule {
     fn new(context: ModuleContext) -> Self {
         let _ = Logger::new().init();
@@ -248,6 +447,9 @@ impl Module for SubtitleModule {
             .enable_media_play_pause()
             .enable_media_get_time()
             .enable_media_get_duration()
+            .enable_media_set_time()
+            .enable_media_next()
+            .enable_media_previous()
             .build();
 
         load_context.register_source(source);
@@ -0,0 +1,257 @@
+//! Playlist state machine: owns track order, the current index and the
+//! repeat mode, and decides what should load next once a track ends.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// How the playlist behaves once the current track finishes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RepeatMode {
+    Off,
+    RepeatOne,
+    RepeatAll,
+    Shuffle,
+}
+
+impl RepeatMode {
+    pub fn from_index(index: i32) -> Self {
+        match index {
+            1 => RepeatMode::RepeatOne,
+            2 => RepeatMode::RepeatAll,
+            3 => RepeatMode::Shuffle,
+            _ => RepeatMode::Off,
+        }
+    }
+}
+
+/// Playback pipeline state, tracked explicitly so that "a track just
+/// ended, should we advance?" has an unambiguous answer instead of being
+/// inferred from `cur_time`/`len` comparisons scattered around.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PlaybackState {
+    Idle,
+    Loading,
+    Playing,
+    Ended,
+}
+
+pub struct Playlist {
+    tracks: Vec<String>,
+    current: usize,
+    pub repeat: RepeatMode,
+    pub state: PlaybackState,
+}
+
+impl Playlist {
+    pub fn new() -> Self {
+        Self {
+            tracks: Vec::new(),
+            current: 0,
+            repeat: RepeatMode::Off,
+            state: PlaybackState::Idle,
+        }
+    }
+
+    /// Replaces the track list, keeping the current index if it still
+    /// falls within bounds.
+    pub fn set_tracks(&mut self, tracks: Vec<String>) {
+        if self.current >= tracks.len() {
+            self.current = 0;
+        }
+        self.tracks = tracks;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tracks.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.tracks.len()
+    }
+
+    pub fn current_path(&self) -> Option<&str> {
+        self.tracks.get(self.current).map(String::as_str)
+    }
+
+    /// Marks the current track as loading; set when a track is picked to
+    /// load, before its path is handed back for `load_track`.
+    pub fn mark_loading(&mut self) {
+        self.state = PlaybackState::Loading;
+    }
+
+    pub fn mark_playing(&mut self) {
+        self.state = PlaybackState::Playing;
+    }
+
+    /// Called from `video_tick` once the loaded track has ended. Returns
+    /// the path of the next track to load, if the repeat mode says
+    /// playback should continue; returns `None` unless the playlist is in
+    /// the `Playing` state.
+    pub fn on_track_ended(&mut self) -> Option<String> {
+        if self.tracks.is_empty() || self.state != PlaybackState::Playing {
+            return None;
+        }
+        self.state = PlaybackState::Ended;
+
+        match self.repeat {
+            RepeatMode::Off if self.current + 1 >= self.tracks.len() => None,
+            RepeatMode::Off => {
+                self.current += 1;
+                self.load_current()
+            }
+            RepeatMode::RepeatOne => self.load_current(),
+            RepeatMode::RepeatAll => {
+                self.current = (self.current + 1) % self.tracks.len();
+                self.load_current()
+            }
+            RepeatMode::Shuffle => {
+                self.current = self.random_index();
+                self.load_current()
+            }
+        }
+    }
+
+    pub fn next(&mut self) -> Option<String> {
+        if self.tracks.is_empty() {
+            return None;
+        }
+        self.current = match self.repeat {
+            RepeatMode::Shuffle => self.random_index(),
+            _ => (self.current + 1) % self.tracks.len(),
+        };
+        self.load_current()
+    }
+
+    pub fn previous(&mut self) -> Option<String> {
+        if self.tracks.is_empty() {
+            return None;
+        }
+        self.current = match self.repeat {
+            RepeatMode::Shuffle => self.random_index(),
+            _ => (self.current + self.tracks.len() - 1) % self.tracks.len(),
+        };
+        self.load_current()
+    }
+
+    /// Advances to the next track by plain sequential index, ignoring
+    /// `repeat`. Used by failure recovery walking the whole playlist once:
+    /// under `RepeatMode::Shuffle`, `next()`'s random pick can repeat an
+    /// index and never visit some of the others within a bounded number of
+    /// attempts, so that walk needs an order `next()` can't promise.
+    pub fn advance_linear(&mut self) -> Option<String> {
+        if self.tracks.is_empty() {
+            return None;
+        }
+        self.current = (self.current + 1) % self.tracks.len();
+        self.load_current()
+    }
+
+    fn load_current(&mut self) -> Option<String> {
+        self.mark_loading();
+        self.current_path().map(str::to_string)
+    }
+
+    fn random_index(&self) -> usize {
+        if self.tracks.len() <= 1 {
+            return 0;
+        }
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        (seed % self.tracks.len() as u64) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn playlist(tracks: &[&str]) -> Playlist {
+        let mut playlist = Playlist::new();
+        playlist.set_tracks(tracks.iter().map(|s| s.to_string()).collect());
+        playlist
+    }
+
+    #[test]
+    fn set_tracks_resets_out_of_bounds_current() {
+        let mut playlist = playlist(&["a", "b", "c"]);
+        playlist.next();
+        playlist.next();
+        assert_eq!(playlist.current_path(), Some("c"));
+
+        playlist.set_tracks(vec!["x".to_string()]);
+        assert_eq!(playlist.current_path(), Some("x"));
+    }
+
+    #[test]
+    fn next_and_previous_wrap_around() {
+        let mut playlist = playlist(&["a", "b", "c"]);
+        assert_eq!(playlist.current_path(), Some("a"));
+        assert_eq!(playlist.next(), Some("b".to_string()));
+        assert_eq!(playlist.next(), Some("c".to_string()));
+        assert_eq!(playlist.next(), Some("a".to_string()));
+        assert_eq!(playlist.previous(), Some("c".to_string()));
+    }
+
+    #[test]
+    fn on_track_ended_does_nothing_unless_playing() {
+        let mut playlist = playlist(&["a", "b"]);
+        assert_eq!(playlist.on_track_ended(), None);
+
+        playlist.mark_playing();
+        assert_eq!(playlist.on_track_ended(), Some("b".to_string()));
+    }
+
+    #[test]
+    fn on_track_ended_repeat_off_stops_at_last_track() {
+        let mut playlist = playlist(&["a", "b"]);
+        playlist.repeat = RepeatMode::Off;
+        playlist.mark_playing();
+        assert_eq!(playlist.on_track_ended(), Some("b".to_string()));
+
+        playlist.mark_playing();
+        assert_eq!(playlist.on_track_ended(), None);
+    }
+
+    #[test]
+    fn on_track_ended_repeat_one_reloads_current_track() {
+        let mut playlist = playlist(&["a", "b"]);
+        playlist.repeat = RepeatMode::RepeatOne;
+        playlist.mark_playing();
+        assert_eq!(playlist.on_track_ended(), Some("a".to_string()));
+        assert_eq!(playlist.current_path(), Some("a"));
+    }
+
+    #[test]
+    fn on_track_ended_repeat_all_wraps_to_first_track() {
+        let mut playlist = playlist(&["a", "b"]);
+        playlist.repeat = RepeatMode::RepeatAll;
+        playlist.mark_playing();
+        assert_eq!(playlist.on_track_ended(), Some("b".to_string()));
+        playlist.mark_playing();
+        assert_eq!(playlist.on_track_ended(), Some("a".to_string()));
+    }
+
+    #[test]
+    fn advance_linear_ignores_repeat_mode_and_visits_every_track_once() {
+        let mut playlist = playlist(&["a", "b", "c"]);
+        playlist.repeat = RepeatMode::Shuffle;
+
+        let mut visited = vec![playlist.current_path().unwrap().to_string()];
+        for _ in 0..2 {
+            visited.push(playlist.advance_linear().unwrap());
+        }
+        assert_eq!(visited, vec!["a", "b", "c"]);
+        // Wraps back around rather than stopping.
+        assert_eq!(playlist.advance_linear(), Some("a".to_string()));
+    }
+
+    #[test]
+    fn repeat_mode_from_index_defaults_to_off() {
+        assert_eq!(RepeatMode::from_index(0), RepeatMode::Off);
+        assert_eq!(RepeatMode::from_index(1), RepeatMode::RepeatOne);
+        assert_eq!(RepeatMode::from_index(2), RepeatMode::RepeatAll);
+        assert_eq!(RepeatMode::from_index(3), RepeatMode::Shuffle);
+        assert_eq!(RepeatMode::from_index(99), RepeatMode::Off);
+    }
+}